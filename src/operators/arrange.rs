@@ -0,0 +1,223 @@
+//! Shared, reference-counted arrangements of a collection by key.
+//!
+//! Several of the `group`/`join` operators each build their own private, key-sorted `Trace` of
+//! their input, by running the same `RadixSorter`/`Compact::from_radix` pipeline that every other
+//! consumer of the same collection also runs. When a collection feeds more than one such operator,
+//! this work (and the indexed state itself) is needlessly duplicated.
+//!
+//! An `Arranged` collection is built once, with `arrange_by_key`, and can then be handed to any
+//! number of `group_arranged` (and eventually `join_arranged`) operators, each of which borrows the
+//! shared, indexed `Trace` rather than re-sorting and re-indexing the input itself.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::default::Default;
+use std::collections::HashMap;
+use std::ops::DerefMut;
+
+use timely::dataflow::*;
+use timely::dataflow::operators::{Map, Unary};
+use timely::dataflow::channels::pact::Exchange;
+use timely::drain::DrainExt;
+
+use ::Data;
+use collection::{LeastUpperBound, Lookup, Trace, Offset};
+use collection::compact::Compact;
+use radix_sort::{RadixSorter, Unsigned};
+
+/// A key-sorted, time-indexed trace of a collection, shared by reference count.
+///
+/// `Arranged` bundles the shared `trace` with a `stream` of the keys that are interesting at each
+/// time; consumers like `group_arranged` attach to `stream` purely for notification and
+/// to learn which keys moved, and read the actual values back out of `trace`. It also keeps around
+/// the `Look` constructor the arrangement itself was built with, so that a consumer needing a
+/// second, private trace (e.g. `group_arranged`'s `result`) can build one with a matching `Lookup`
+/// rather than falling back to some unrelated default.
+pub struct Arranged<G: Scope, K, V, Look> where G::Timestamp: LeastUpperBound {
+    /// The shared, key-indexed trace of the arranged collection.
+    pub trace: Rc<RefCell<Trace<K, G::Timestamp, V, Look>>>,
+    /// For each time, the keys that are "interesting" at that time: an input changed them at that
+    /// time or at an earlier time from which this one follows.
+    pub stream: Stream<G, Vec<K>>,
+    /// Builds a fresh, empty `Look` consistent with the one `trace` itself uses.
+    pub look: Rc<Fn()->Look>,
+}
+
+impl<G: Scope, K, V, Look> Clone for Arranged<G, K, V, Look> where G::Timestamp: LeastUpperBound {
+    fn clone(&self) -> Self {
+        Arranged { trace: self.trace.clone(), stream: self.stream.clone(), look: self.look.clone() }
+    }
+}
+
+/// Extension trait to arrange a stream of `(key, val)` updates by `key`, once, for sharing.
+pub trait ArrangeByKey<G: Scope, K: Data+Default, V: Data+Default> : Unary<G, ((K,V),i32)>+Map<G, ((K,V),i32)>
+    where G::Timestamp: LeastUpperBound {
+
+    /// Sorts and indexes `self` by key, producing an `Arranged` collection that can be shared
+    /// among several `group_arranged` (or other arrangement-consuming) operators.
+    ///
+    /// Always builds its `HashMap` lookup via `|_| HashMap::new()`, so unlike `GroupUnsigned::group_u`
+    /// it does not yet shave key bits for a peer-aware dense lookup; use `arrange_by_key_inner` with
+    /// a custom `look` if that matters for your key type.
+    ///
+    /// TODO: give `arrange_by_key` the same peer-aware `look` treatment `group_u` has, so sharing
+    /// an arrangement doesn't mean giving up that optimization.
+    fn arrange_by_key<Part, U, KH>(&self, part: Part, key_h: KH) -> Arranged<G, K, V, HashMap<K, Offset>>
+        where Part: Fn(&(K,V))->u64+'static,
+              U: Unsigned+Default,
+              KH: Fn(&K)->U+'static {
+        self.arrange_by_key_inner(part, key_h, |_| HashMap::new())
+    }
+
+    /// As `arrange_by_key`, but with an explicit choice of `Lookup` implementation, for example a
+    /// dense vector lookup for unsigned integer keys.
+    fn arrange_by_key_inner<Part, U, KH, Look, LookG>(&self, part: Part, key_h: KH, look: LookG) -> Arranged<G, K, V, Look>
+        where Part: Fn(&(K,V))->u64+'static,
+              U: Unsigned+Default,
+              KH: Fn(&K)->U+'static,
+              Look: Lookup<K, Offset>+'static,
+              LookG: Fn(u64)->Look+'static;
+}
+
+impl<G: Scope, K: Data+Ord+Clone+Debug+Default+'static, V: Data+Ord+Clone+Default+Debug+'static, S> ArrangeByKey<G, K, V> for S
+where G::Timestamp: LeastUpperBound,
+      S: Unary<G, ((K,V), i32)>+Map<G, ((K,V), i32)> {
+
+    fn arrange_by_key_inner<Part, U, KH, Look, LookG>(&self, part: Part, key_h: KH, look: LookG) -> Arranged<G, K, V, Look>
+        where Part: Fn(&(K,V))->u64+'static,
+              U: Unsigned+Default,
+              KH: Fn(&K)->U+'static,
+              Look: Lookup<K, Offset>+'static,
+              LookG: Fn(u64)->Look+'static {
+
+        // keep the `Look` constructor around (as the shift used for `0` below) so a later
+        // consumer of the arrangement can build a second, private trace with a matching `Lookup`.
+        let look: Rc<Fn()->Look> = Rc::new(move || look(0));
+
+        let trace = Rc::new(RefCell::new(Trace::new(look())));
+        let result = trace.clone();
+
+        let exch = Exchange::new(move |&(ref kv,_)| part(kv));
+        let mut sorter = RadixSorter::new();
+        let mut inputs = Vec::new();
+        let mut to_do = Vec::new();
+
+        let stream = self.unary_notify(exch, "ArrangeByKey", vec![], move |input, output, notificator| {
+
+            // 1. read each input, and stash it in our staging area.
+            while let Some((time, data)) = input.next() {
+                notificator.notify_at(&time);
+                inputs.entry_or_insert(time.clone(), || Vec::new())
+                      .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+            }
+
+            // 2. go through each time of interest that has reached completion.
+            while let Some((index, _count)) = notificator.next() {
+
+                if let Some(mut queue) = inputs.remove_key(&index) {
+
+                    let compact = if queue.len() > 1 {
+                        for element in queue.into_iter() {
+                            sorter.extend(element.into_iter(), &|x: &(K,V)| key_h(&x.0));
+                        }
+                        let mut sorted = sorter.finish(&|x: &(K,V)| key_h(&x.0));
+                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
+                        sorted.truncate(256);
+                        sorter.recycle(sorted);
+                        result
+                    }
+                    else {
+                        let mut vec = queue.pop().unwrap();
+                        let mut vec = vec.drain_temp().collect::<Vec<_>>();
+                        vec.sort_by(|x,y| key_h(&x.0).cmp(&key_h(&y.0)));
+                        Compact::from_radix(&mut vec![vec], &|k| key_h(k))
+                    };
+
+                    let mut trace = trace.borrow_mut();
+
+                    if let Some(compact) = compact {
+                        for key in &compact.keys {
+                            for time in trace.interesting_times(key, index.clone()).iter() {
+                                let mut queue = to_do.entry_or_insert((*time).clone(), || { notificator.notify_at(time); Vec::new() });
+                                queue.push((*key).clone());
+                            }
+                        }
+
+                        trace.set_difference(index.clone(), compact);
+                    }
+                }
+
+                if let Some(mut keys) = to_do.remove_key(&index) {
+                    keys.sort_by(|x,y| key_h(x).cmp(&key_h(y)));
+                    keys.dedup();
+                    output.session(&index).give(keys);
+                }
+            }
+        });
+
+        Arranged { trace: result, stream: stream, look: look }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use timely;
+    use timely::dataflow::operators::{Input, Inspect};
+
+    use super::ArrangeByKey;
+    use super::super::group::GroupArranged;
+
+    // Arranges once and attaches two independent `group_arranged` consumers to the same shared
+    // trace, then checks both see correct incremental (insert then retract) output. This is the
+    // scenario `c299279` fixed a bug in: `group_arranged`'s private `result` trace used
+    // `Look::new()` instead of the arrangement's own constructor, so it could drift out of sync
+    // with the shared `source` trace it reads from.
+    #[test]
+    fn two_consumers_see_consistent_incremental_output() {
+        timely::execute(timely::Configuration::Thread, |root| {
+            let result_a = Rc::new(RefCell::new(Vec::new()));
+            let result_a2 = result_a.clone();
+            let result_b = Rc::new(RefCell::new(Vec::new()));
+            let result_b2 = result_b.clone();
+
+            let mut input = root.scoped::<u64,_,_>(|scope| {
+                let (input, stream) = scope.new_input();
+
+                let arranged = stream.arrange_by_key(|&(ref k,_)| k.hashed(), |k: &i64| k.hashed());
+
+                arranged.group_arranged(
+                    |_key, v: &i64| *v,
+                    |_key, vals, output| {
+                        let sum = vals.fold(0i64, |acc, (v, w)| acc + (*v) * (w as i64));
+                        if sum != 0 { output.push((sum, 1)); }
+                    })
+                    .inspect(move |x| result_a2.borrow_mut().push(x.clone()));
+
+                arranged.group_arranged(
+                    |_key, v: &i64| *v,
+                    |_key, vals, output| {
+                        let sum = vals.fold(0i64, |acc, (v, w)| acc + (*v) * (w as i64));
+                        if sum != 0 { output.push((sum, 1)); }
+                    })
+                    .inspect(move |x| result_b2.borrow_mut().push(x.clone()));
+
+                input
+            });
+
+            input.send(((7i64, 3i64), 1));
+            input.advance_to(1);
+            input.send(((7i64, 3i64), -1));
+            input.close();
+            while root.step() { }
+
+            for result in &[&result_a, &result_b] {
+                let net: i32 = result.borrow().iter().map(|&(_, w)| w).sum();
+                assert_eq!(net, 0);
+            }
+        }).unwrap();
+    }
+}
@@ -42,7 +42,7 @@ use itertools::Itertools;
 use ::Data;
 use timely::dataflow::*;
 use timely::dataflow::operators::{Map, Unary};
-use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::channels::pact::{Exchange, Pipeline};
 use timely::drain::DrainExt;
 
 use collection::{LeastUpperBound, Lookup, Trace, Offset};
@@ -52,6 +52,8 @@ use iterators::coalesce::Coalesce;
 use radix_sort::{RadixSorter, Unsigned};
 use collection::compact::Compact;
 
+use super::arrange::Arranged;
+
 /// Extension trait for the `group` differential dataflow method
 pub trait Group<G: Scope, K: Data+Default, V: Data+Default> : GroupBy<G, (K,V)>
     where G::Timestamp: LeastUpperBound {
@@ -66,24 +68,255 @@ where G::Timestamp: LeastUpperBound,
       S: Unary<G, ((K,V), i32)>+Map<G, ((K,V), i32)> { }
 
 
-pub trait GroupUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data+Default+Debug> : GroupBy<G, (U,V)>
-    where G::Timestamp: LeastUpperBound {
+/// Extension trait for the `group_u` differential dataflow method.
+///
+/// Bound directly on `Stream` (rather than anything implementing `Unary`+`Map`) so that
+/// `.scope()` is reachable: this lets the dense-vector `Lookup` see how many peer workers share
+/// the key space and, when `peers` is a power of two, right-shift by `log2(peers)` bits,
+/// allocating a vector sized for the keys landing on this worker rather than the full key space
+/// on every worker. For other peer counts no bits are shaved (see `peer_shift`).
+pub trait GroupUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data+Default+Debug> {
+    fn group_u<L, V2: Data+Ord+Default+Debug>(&self, logic: L) -> Stream<G, ((U,V2),i32)>
+        where L: Fn(&U, &mut CollectionIterator<V>, &mut Vec<(V2, i32)>)+'static;
+}
+
+impl<G: Scope, U: Unsigned+Data+Default, V: Data+Ord+Default+Debug> GroupUnsigned<G, U, V> for Stream<G, ((U,V), i32)>
+where G::Timestamp: LeastUpperBound {
     fn group_u<L, V2: Data+Ord+Default+Debug>(&self, logic: L) -> Stream<G, ((U,V2),i32)>
         where L: Fn(&U, &mut CollectionIterator<V>, &mut Vec<(V2, i32)>)+'static {
+
+            let shift = peer_shift(self.scope().peers() as u64);
+
             self.group_by_inner(
                 |x| x,
                 |&(ref k,_)| k.as_u64(),
                 |k| k.clone(),
                 |k, v| (k.clone(), (*v).clone()),
-                |x| (Vec::new(), x),
+                move |_| (Vec::new(), shift),
                 logic)
     }
 }
 
-// implement `GroupBy` for any stream implementing `Unary` and `Map` (most of them).
-impl<G: Scope, U: Unsigned+Data+Default, V: Data+Ord+Default+Debug, S> GroupUnsigned<G, U, V> for S
+// timely's `Exchange` partitions by `hash % peers`, not by masking low bits, so `key.as_u64() >>
+// shift` only agrees with that partitioning (and so is safe to use as a dense index with no
+// collisions across workers) when `peers` is itself a power of two, in which case
+// `x % peers == x & (peers-1)` and shifting by `log2(peers)` exactly divides the key space among
+// workers. For any other peer count, shaving bits would alias keys from different workers onto
+// the same slot, so fall back to no shift at all (wasteful, but correct) rather than guess at a
+// collision-free scheme.
+fn peer_shift(peers: u64) -> u64 {
+    if peers.is_power_of_two() { peers.trailing_zeros() as u64 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::peer_shift;
+
+    #[test]
+    fn shaves_bits_only_for_power_of_two_peer_counts() {
+        assert_eq!(peer_shift(1), 0);
+        assert_eq!(peer_shift(2), 1);
+        assert_eq!(peer_shift(3), 0);
+        assert_eq!(peer_shift(4), 2);
+        assert_eq!(peer_shift(8), 3);
+    }
+}
+
+
+// Shared low-level implementation for `distinct`, `count`, and `threshold`. All three reduce a
+// key's values down to at most one output value as a pure function of the key's total weight, so
+// unlike `group_by_inner` they never need to invoke a per-value `Logic` closure over a
+// `CollectionIterator`, nor build the general `Vec<(V2,i32)>` buffer it fills for arbitrarily many
+// output values: the total is summed directly off `source`, `flag` turns it into at most one
+// `V2`, and that single value is pushed straight into the diff against `result` below.
+fn group_by_flag<
+    G:     Scope,
+    D1:    Data+Eq,
+    K:     Ord+Clone+Debug+Hash+'static,
+    V2:    Ord+Clone+Default+Debug+'static,
+    D2:    Data,
+    KV:    Fn(D1)->(K,())+'static,
+    Part:  Fn(&D1)->u64+'static,
+    U:     Unsigned+Default,
+    KH:    Fn(&K)->U+'static,
+    Flag:  Fn(i32)->Option<V2>+'static,
+    Reduc: Fn(&K, &V2)->D2+'static,
+    S,
+>
+(stream: &S, kv: KV, part: Part, key_h: KH, reduc: Reduc, flag: Flag) -> Stream<G, (D2, i32)>
 where G::Timestamp: LeastUpperBound,
-      S: GroupBy<G, (U,V)> { }
+      S: Unary<G, (D1, i32)>+Map<G, (D1, i32)> {
+
+    let mut source = Trace::new(HashMap::new());
+    let mut result = Trace::new(HashMap::new());
+
+    let mut inputs = Vec::new();
+    let mut to_do = Vec::new();
+
+    let mut heap1 = vec![];
+    let mut heap2 = vec![];
+
+    let exch = Exchange::new(move |&(ref x,_)| part(x));
+    let mut sorter = RadixSorter::new();
+
+    stream.unary_notify(exch, "GroupByFlag", vec![], move |input, output, notificator| {
+
+        // 1. read each input, and stash it in our staging area
+        while let Some((time, data)) = input.next() {
+            notificator.notify_at(&time);
+            inputs.entry_or_insert(time.clone(), || Vec::new())
+                  .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+        }
+
+        // 2. go through each time of interest that has reached completion
+        while let Some((index, _count)) = notificator.next() {
+
+            // 2a. fetch any data associated with this time.
+            if let Some(mut queue) = inputs.remove_key(&index) {
+
+                let compact = if queue.len() > 1 {
+                    for element in queue.into_iter() {
+                        sorter.extend(element.into_iter().map(|(d,w)| (kv(d),w)), &|x| key_h(&(x.0).0));
+                    }
+                    let mut sorted = sorter.finish(&|x| key_h(&(x.0).0));
+                    let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
+                    sorted.truncate(256);
+                    sorter.recycle(sorted);
+                    result
+                }
+                else {
+                    let mut vec = queue.pop().unwrap();
+                    let mut vec = vec.drain_temp().map(|(d,w)| (kv(d),w)).collect::<Vec<_>>();
+                    vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
+                    Compact::from_radix(&mut vec![vec], &|k| key_h(k))
+                };
+
+                if let Some(compact) = compact {
+                    for key in &compact.keys {
+                        for time in source.interesting_times(key, index.clone()).iter() {
+                            let mut queue = to_do.entry_or_insert((*time).clone(), || { notificator.notify_at(time); Vec::new() });
+                            queue.push((*key).clone());
+                        }
+                    }
+                    source.set_difference(index.clone(), compact);
+                }
+            }
+
+            let mut session = output.session(&index);
+
+            if let Some(mut keys) = to_do.remove_key(&index) {
+
+                keys.sort_by(|x,y| (key_h(&x), x).cmp(&(key_h(&y), y)));
+                keys.dedup();
+
+                let mut accumulation = Compact::new(0,0);
+
+                for key in keys {
+
+                    // sum weights directly, rather than handing an arbitrary `Logic` the whole
+                    // `CollectionIterator` to do it; `distinct`/`count`/`threshold` only ever
+                    // care about the total, never the individual values (there are none: `V1 = ()`).
+                    let total = unsafe { source.get_collection_using(&key, &index, &mut heap1) }
+                        .fold(0, |sum, (_, wgt)| sum + wgt);
+
+                    // at most one output value per key, so no sort/dedup of a general buffer.
+                    let flagged = flag(total);
+
+                    let mut compact = accumulation.session();
+                    for (val, wgt) in Coalesce::coalesce(unsafe { result.get_collection_using(&key, &index, &mut heap2) }
+                                                               .map(|(v, w)| (v,-w))
+                                                               .merge_by(flagged.iter().map(|v| (v, 1)), |x,y| {
+                                                                    x.0.cmp(&y.0)
+                                                               }))
+                    {
+                        session.give((reduc(&key, val), wgt));
+                        compact.push(val.clone(), wgt);
+                    }
+                    compact.done(key);
+                }
+
+                if accumulation.vals.len() > 0 {
+                    result.set_difference(index.clone(), accumulation);
+                }
+            }
+        }
+    })
+}
+
+/// Extension trait for the `distinct` differential dataflow method.
+///
+/// `distinct` collapses any positive total multiplicity for a key down to weight one, and drops
+/// keys with non-positive multiplicity entirely. It is a thin convenience wrapper around
+/// `group_by_flag` with a fixed `V1 = ()`; unlike `group_by`, callers don't need to supply their
+/// own `kv`/`part`/`key_h`/`reduc` for the common case of "just the keys, with weights".
+///
+/// `count` and `threshold`, below, are the same wrapper with a different `flag`.
+pub trait Distinct<G: Scope, K: Data+Default> : GroupBy<G, K>
+    where G::Timestamp: LeastUpperBound {
+    fn distinct(&self) -> Stream<G, (K, i32)> {
+        group_by_flag(
+            self,
+            |k| (k, ()),
+            |k| k.hashed(),
+            |k| k.hashed(),
+            |k, _: &()| (*k).clone(),
+            |total| if total > 0 { Some(()) } else { None })
+    }
+}
+
+impl<G: Scope, K: Data+Default, S> Distinct<G, K> for S
+where G::Timestamp: LeastUpperBound,
+      S: GroupBy<G, K> { }
+
+
+/// Extension trait for the `count` differential dataflow method.
+///
+/// `count` reduces a collection to `(key, total_weight)` pairs, one per distinct key: unlike
+/// `distinct`, which reports whether the total was positive, `count` reports the total itself.
+pub trait Count<G: Scope, K: Data+Default> : GroupBy<G, K>
+    where G::Timestamp: LeastUpperBound {
+    fn count(&self) -> Stream<G, ((K,i32), i32)> {
+        group_by_flag(
+            self,
+            |k| (k, ()),
+            |k| k.hashed(),
+            |k| k.hashed(),
+            |k, v: &i32| ((*k).clone(), *v),
+            |total| if total != 0 { Some(total) } else { None })
+    }
+}
+
+impl<G: Scope, K: Data+Default, S> Count<G, K> for S
+where G::Timestamp: LeastUpperBound,
+      S: GroupBy<G, K> { }
+
+
+/// Extension trait for the `threshold` differential dataflow method.
+///
+/// `threshold` reduces a collection to `(key, f(total_weight))` pairs, applying a user-supplied
+/// transformation to each key's accumulated weight. Keys whose transformed weight is zero are
+/// dropped. `threshold(|w| if w > 0 {1} else {0})` keeps the same keys as `distinct`, but emits
+/// them as `(key, 1)` pairs rather than as bare keys.
+pub trait Threshold<G: Scope, K: Data+Default> : GroupBy<G, K>
+    where G::Timestamp: LeastUpperBound {
+    fn threshold<F>(&self, thresh: F) -> Stream<G, ((K,i32), i32)>
+        where F: Fn(i32)->i32+'static {
+        group_by_flag(
+            self,
+            |k| (k, ()),
+            |k| k.hashed(),
+            |k| k.hashed(),
+            |k, v: &i32| ((*k).clone(), *v),
+            move |total| {
+                let thresholded = thresh(total);
+                if thresholded != 0 { Some(thresholded) } else { None }
+            })
+    }
+}
+
+impl<G: Scope, K: Data+Default, S> Threshold<G, K> for S
+where G::Timestamp: LeastUpperBound,
+      S: GroupBy<G, K> { }
 
 
 // implement `GroupBy` for any stream implementing `Unary` and `Map` (most of them).
@@ -168,16 +401,13 @@ where G::Timestamp: LeastUpperBound {
     (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Stream<G, (D2, i32)> {
 
         // A pair of source and result `CollectionTrace` instances.
-        // TODO : The hard-coded 0 means we don't know how many bits we can shave off of each int
-        // TODO : key, which is fine for `HashMap` but less great for integer keyed maps, which use
-        // TODO : dense vectors (sparser as number of workers increases).
-        // TODO : At the moment, we don't have access to the stream's underlying .scope() method,
-        // TODO : which is what would let us see the number of peers, because we only know that
-        // TODO : the type also implements the `Unary` and `Map` traits, not that it is a `Stream`.
-        // TODO : We could implement this just for `Stream`, but would have to repeat the trait
-
-        // TODO : method signature boiler-plate, rather than use default implemenations.
-        // let mut trace =  OperatorTrace::<K, G::Timestamp, V1, V2, Look>::new(|| look(0));
+        // `look` is always called with a shift of `0` here, which is fine for `HashMap` but
+        // wasteful for the dense-vector `Lookup` used by integer-keyed maps, whose array would
+        // ideally be sized for the keys landing on this worker rather than the full key space.
+        // Picking a real shift here would require `self.scope()` to learn the number of peers,
+        // which isn't available on the generic `S: Unary+Map` that `Self` ranges over in this
+        // trait; `GroupUnsigned::group_u`, implemented directly for `Stream`, reads `.scope()`
+        // itself and passes a `look` that ignores this `0` and uses the shift it computed.
         let mut source = Trace::new(look(0));
         let mut result = Trace::new(look(0));
 
@@ -303,3 +533,189 @@ where G::Timestamp: LeastUpperBound {
         })
     }
 }
+
+/// Extension trait for applying `group`-style reductions to an `Arranged` collection.
+///
+/// Unlike `group_by_inner`, which builds its own private `source` trace and re-sorts its input
+/// every epoch, `group_arranged` reads directly from the shared, already-indexed `trace` of an
+/// `Arranged` collection. Any number of `group_arranged` operators (and `join` operators, in time)
+/// can share the same arrangement, rather than each re-sorting and re-indexing the same input.
+pub trait GroupArranged<G: Scope, K: Ord+Clone+Debug+'static, V1: Ord+Clone+Default+Debug+'static, Look: Lookup<K, Offset>+'static>
+    where G::Timestamp: LeastUpperBound {
+
+    /// Applies `logic` to the values for each key in the arrangement, as `group_by_inner` does,
+    /// but without re-sorting or re-indexing: the arrangement's shared `trace` is read directly.
+    fn group_arranged<
+        V2:    Ord+Clone+Default+Debug+'static,
+        D2:    Data,
+        Logic: Fn(&K, &mut CollectionIterator<V1>, &mut Vec<(V2, i32)>)+'static,
+        Reduc: Fn(&K, &V2)->D2+'static,
+    >(&self, reduc: Reduc, logic: Logic) -> Stream<G, (D2, i32)>;
+}
+
+impl<G: Scope, K: Ord+Clone+Debug+'static, V1: Ord+Clone+Default+Debug+'static, Look: Lookup<K, Offset>+'static> GroupArranged<G, K, V1, Look> for Arranged<G, K, V1, Look>
+where G::Timestamp: LeastUpperBound {
+
+    fn group_arranged<
+        V2:    Ord+Clone+Default+Debug+'static,
+        D2:    Data,
+        Logic: Fn(&K, &mut CollectionIterator<V1>, &mut Vec<(V2, i32)>)+'static,
+        Reduc: Fn(&K, &V2)->D2+'static,
+    >(&self, reduc: Reduc, logic: Logic) -> Stream<G, (D2, i32)> {
+
+        let source = self.trace.clone();
+        // build `result`'s `Lookup` the same way the arrangement built `source`'s, rather than
+        // via `Look::new()`, so the two traces stay consistent (e.g. the same dense-vector shift).
+        let result_look = self.look.clone();
+        let mut result = Trace::new(result_look());
+
+        // keys to process at each time, as reported by the shared arrangement's `stream`.
+        let mut to_do = Vec::new();
+
+        let mut heap1 = vec![];
+        let mut heap2 = vec![];
+        let mut buffer = vec![];
+
+        self.stream.unary_notify(Pipeline, "GroupArranged", vec![], move |input, output, notificator| {
+
+            // 1. the arrangement already did the work of deciding which keys are interesting at
+            // which times; just stash the keys it reports for each time.
+            while let Some((time, data)) = input.next() {
+                notificator.notify_at(&time);
+                for keys in data.drain_temp() {
+                    to_do.entry_or_insert(time.clone(), || Vec::new()).extend(keys);
+                }
+            }
+
+            while let Some((index, _count)) = notificator.next() {
+
+                let mut session = output.session(&index);
+                let mut accumulation = Compact::new(0, 0);
+
+                if let Some(keys) = to_do.remove_key(&index) {
+
+                    let source = source.borrow();
+
+                    for key in keys {
+
+                        let mut input = unsafe { source.get_collection_using(&key, &index, &mut heap1) };
+
+                        if input.peek().is_some() { logic(&key, &mut input, &mut buffer); }
+
+                        buffer.sort_by(|x,y| x.0.cmp(&y.0));
+
+                        let mut compact = accumulation.session();
+                        for (val, wgt) in Coalesce::coalesce(unsafe { result.get_collection_using(&key, &index, &mut heap2) }
+                                                                   .map(|(v, w)| (v,-w))
+                                                                   .merge_by(buffer.iter().map(|&(ref v, w)| (v, w)), |x,y| {
+                                                                        x.0.cmp(&y.0)
+                                                                   }))
+                        {
+                            session.give((reduc(&key, val), wgt));
+                            compact.push(val.clone(), wgt);
+                        }
+                        compact.done(key);
+                        buffer.clear();
+                    }
+                }
+
+                if accumulation.vals.len() > 0 {
+                    result.set_difference(index.clone(), accumulation);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod flag_tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use timely;
+    use timely::dataflow::operators::{Input, Inspect};
+
+    use super::{Distinct, Count, Threshold};
+
+    // Regression coverage for the sign bug `c5bb45d` fixed: `distinct` must drop a key once a
+    // later retraction nets its total weight back to zero, not just whenever *any* value for it
+    // is still present.
+    #[test]
+    fn distinct_disappears_once_retracted_to_zero() {
+        timely::execute(timely::Configuration::Thread, |root| {
+            let result = Rc::new(RefCell::new(Vec::new()));
+            let result2 = result.clone();
+
+            let mut input = root.scoped::<u64,_,_>(|scope| {
+                let (input, stream) = scope.new_input();
+                stream
+                    .distinct()
+                    .inspect(move |x| result2.borrow_mut().push(x.clone()));
+                input
+            });
+
+            input.send((7i64, 1));
+            input.advance_to(1);
+            input.send((7i64, -1));
+            input.close();
+            while root.step() { }
+
+            let net: i32 = result.borrow().iter().filter(|&&(k, _)| k == 7).map(|&(_, w)| w).sum();
+            assert_eq!(net, 0);
+        }).unwrap();
+    }
+
+    // Same retraction shape as `distinct_disappears_once_retracted_to_zero`, but through `count`.
+    #[test]
+    fn count_disappears_once_retracted_to_zero() {
+        timely::execute(timely::Configuration::Thread, |root| {
+            let result = Rc::new(RefCell::new(Vec::new()));
+            let result2 = result.clone();
+
+            let mut input = root.scoped::<u64,_,_>(|scope| {
+                let (input, stream) = scope.new_input();
+                stream
+                    .count()
+                    .inspect(move |x| result2.borrow_mut().push(x.clone()));
+                input
+            });
+
+            input.send((7i64, 1));
+            input.advance_to(1);
+            input.send((7i64, -1));
+            input.close();
+            while root.step() { }
+
+            let net: i32 = result.borrow().iter().filter(|&&((k, _), _)| k == 7).map(|&(_, w)| w).sum();
+            assert_eq!(net, 0);
+        }).unwrap();
+    }
+
+    // Same retraction shape again, but crossing a `threshold` boundary rather than dropping to
+    // zero: the total goes 3 (above threshold) down to 1 (below it), so the thresholded value
+    // should disappear just as it would if the key vanished entirely.
+    #[test]
+    fn threshold_disappears_once_retracted_below_threshold() {
+        timely::execute(timely::Configuration::Thread, |root| {
+            let result = Rc::new(RefCell::new(Vec::new()));
+            let result2 = result.clone();
+
+            let mut input = root.scoped::<u64,_,_>(|scope| {
+                let (input, stream) = scope.new_input();
+                stream
+                    .threshold(|w| if w > 2 { 1 } else { 0 })
+                    .inspect(move |x| result2.borrow_mut().push(x.clone()));
+                input
+            });
+
+            input.send((7i64, 3));
+            input.advance_to(1);
+            input.send((7i64, -2));
+            input.close();
+            while root.step() { }
+
+            let net: i32 = result.borrow().iter().filter(|&&((k, _), _)| k == 7).map(|&(_, w)| w).sum();
+            assert_eq!(net, 0);
+        }).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! A hierarchical alternative to `group`/`group_by` for associative, commutative reductions.
+//!
+//! `group_by_inner` re-invokes its `logic` over *all* values for a key whenever any one of them
+//! changes, which costs O(values) work per update. When `logic` is associative and commutative
+//! (`min`, `max`, `sum`, top-k, and the like) we can instead bucket the values, reduce within each
+//! bucket, and repeat the bucketing and reduction over the bucket outputs until a single value
+//! remains per key. Each level of the tree is its own keyed collection with `fanout` times fewer
+//! buckets than the level below, and the last level has one bucket per key. A later level assigns
+//! each partial result to a bucket by the hash of that partial, not by the bucket it came from, so
+//! a change to one input value dirties one bucket at the base, and at each later level the buckets
+//! of the old and new partials that changed below it; this is not bounded to one bucket per level.
+
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::default::Default;
+
+use ::Data;
+use timely::dataflow::*;
+
+use collection::LeastUpperBound;
+use collection::trace::CollectionIterator;
+use radix_sort::Unsigned;
+
+use super::GroupBy;
+
+/// Extension trait for the hierarchical `group_hierarchical` differential dataflow method.
+pub trait GroupHierarchical<G: Scope, K: Data+Hash+Ord+Clone+Debug+Default, V: Data+Hash+Ord+Clone+Default+Debug>
+    : GroupBy<G, (K,V)>
+    where G::Timestamp: LeastUpperBound {
+
+    /// Reduces the values for each key through a balanced tree of `fanout`-to-one bucketed
+    /// reductions, `levels` deep, rather than a single reduction over all of a key's values.
+    ///
+    /// `logic` is applied identically at every level: once to reduce each bucket of raw values,
+    /// and again to reduce each bucket of already-reduced partials from the level below. This
+    /// requires `logic` to be order-insensitive and to produce the same result however its inputs
+    /// get rebucketed, which holds for associative, commutative reductions but not in general.
+    ///
+    /// `fanout` sets the branching factor of the tree; `levels` should be chosen so that
+    /// `fanout.pow(levels)` comfortably exceeds the largest number of values expected under any
+    /// one key, since the dataflow graph fixes the number of levels at construction time.
+    fn group_hierarchical<L>(&self, fanout: u64, levels: u32, logic: L) -> Stream<G, ((K,V),i32)>
+        where L: Fn(&K, &mut CollectionIterator<V>, &mut Vec<(V,i32)>)+'static+Clone
+    {
+        assert!(fanout > 1);
+        assert!(levels > 0);
+
+        // Level 0 buckets the raw `(key, val)` pairs by `hash(val) % fanout.pow(levels-1)` and
+        // reduces within each bucket; `key_h` mixes the bucket into the key's hash so buckets of
+        // the same key sort and partition together.
+        let mut stage = bucket_and_reduce(self, fanout.pow(levels - 1), logic.clone());
+
+        // Every later level re-buckets and re-reduces the previous level's output into `fanout`
+        // times fewer buckets, down to a single bucket per key at the last level.
+        for level in 1..levels {
+            stage = bucket_and_reduce(&stage, fanout.pow(levels - 1 - level), logic.clone());
+        }
+
+        stage
+    }
+}
+
+impl<G: Scope, K: Data+Hash+Ord+Clone+Debug+Default, V: Data+Hash+Ord+Clone+Default+Debug, S> GroupHierarchical<G, K, V> for S
+where G::Timestamp: LeastUpperBound,
+      S: GroupBy<G, (K,V)> { }
+
+// Buckets `stream`'s `(key, val)` pairs by `hash(val) % buckets` and reduces within each bucket,
+// re-emitting `(key, val)` pairs (at most `buckets` of them per key).
+fn bucket_and_reduce<G, K, V, L, S>(stream: &S, buckets: u64, logic: L) -> Stream<G, ((K,V),i32)>
+    where G: Scope,
+          G::Timestamp: LeastUpperBound,
+          K: Data+Hash+Ord+Clone+Debug+Default,
+          V: Data+Hash+Ord+Clone+Default+Debug,
+          L: Fn(&K, &mut CollectionIterator<V>, &mut Vec<(V,i32)>)+'static,
+          S: GroupBy<G, (K,V)>,
+{
+    // `part` sees the *pre-bucketing* `(key, val)` pair, so it must independently recompute the
+    // same bucket that `kv` assigns below, rather than destructuring the already-bucketed key.
+    stream.group_by(
+        move |(key, val): (K,V)| {
+            let bucket = val.hashed().as_u64() % buckets;
+            ((key, bucket), val)
+        },
+        move |&(ref key, ref val)| key.hashed().as_u64().wrapping_mul(buckets).wrapping_add(val.hashed().as_u64() % buckets),
+        move |&(ref key, bucket)| key.hashed().as_u64().wrapping_mul(buckets).wrapping_add(bucket),
+        |&(ref key, _), val: &V| (key.clone(), val.clone()),
+        move |bucketed_key: &(K,u64), vals: &mut CollectionIterator<V>, output: &mut Vec<(V,i32)>| {
+            logic(&bucketed_key.0, vals, output)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use timely;
+    use timely::dataflow::operators::{Input, Inspect};
+
+    use super::GroupHierarchical;
+
+    // `fanout = 2`, `levels = 3` gives room for up to 8 values under a key; feeding it 5 forces
+    // more than one level of `bucket_and_reduce` to run before a single value survives to the
+    // root, so a mis-sized bucket key or a rebucketing that doesn't fully funnel down would show
+    // up as more than one output record for the key, or the wrong total.
+    #[test]
+    fn sums_more_values_than_a_single_bucket_holds() {
+        timely::execute(timely::Configuration::Thread, |root| {
+            let result = Rc::new(RefCell::new(Vec::new()));
+            let result2 = result.clone();
+
+            let mut input = root.scoped::<u64,_,_>(|scope| {
+                let (input, stream) = scope.new_input();
+                stream
+                    .group_hierarchical(2, 3, |_key: &(), vals, output| {
+                        let sum = vals.fold(0i64, |acc, (v, w)| acc + (*v) * (w as i64));
+                        if sum != 0 { output.push((sum, 1)); }
+                    })
+                    .inspect(move |x| result2.borrow_mut().push(x.clone()));
+                input
+            });
+
+            for v in 1i64..6 {
+                input.send((((), v), 1));
+            }
+            input.close();
+            while root.step() { }
+
+            assert_eq!(&result.borrow()[..], &[(((), 15), 1)]);
+        }).unwrap();
+    }
+}
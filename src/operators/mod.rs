@@ -8,12 +8,16 @@
 //! other ways compatible with timely dataflow. In fact, many operators are currently absent because
 //! their timely dataflow analogues are sufficient (e.g. `map`, `filter`, `concat`).
 
-pub use self::group::{GroupBy, Group, GroupUnsigned};
+pub use self::group::{GroupBy, Group, GroupUnsigned, GroupArranged, Distinct, Count, Threshold};
 pub use self::consolidate::ConsolidateExt;
 pub use self::iterate::IterateExt;
 pub use self::join::{JoinBy, Join, JoinUnsigned};
+pub use self::arrange::{Arranged, ArrangeByKey};
+pub use self::hierarchical::GroupHierarchical;
 
 pub mod group;
 pub mod consolidate;
 pub mod iterate;
 pub mod join;
+pub mod arrange;
+pub mod hierarchical;